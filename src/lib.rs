@@ -65,8 +65,21 @@
 //!
 //! - placement\_in\_syntax and placement\_new\_protocol are required,
 //! see https://github.com/rust-lang/rust/issues/27779
+//!
+//! ## Using with the standard collections
+//!
+//! `Allocator` also implements the (unstable) `std::alloc::Allocator` trait,
+//! so it can be passed to the `_in` constructors of the standard collections
+//! to have their backing storage come from the arena instead of the global
+//! heap, e.g. `Vec::new_in(&allocator)` or `Box::new_in(x, &allocator)`.
+//! Everything allocated this way is reclaimed in bulk when the `Allocator`
+//! scope drops, just like allocations made through `alloc`/`alloc_slice`.
 
-use std::cell::RefCell;
+#![feature(allocator_api)]
+
+use std::alloc::{AllocError, Layout};
+use std::cell::{Cell, RefCell};
+use std::ptr::NonNull;
 use std::{cmp, mem, ptr};
 
 /// A block of bytes used to back allocations requested from the `MemoryArena`.
@@ -118,26 +131,32 @@ fn align_address(ptr: *const u8, align: usize) -> usize {
 
 /// Provides the backing storage to serve allocations requested by an `Allocator`.
 ///
-/// The `MemoryArena` allocates blocks of fixed size on demand as its existing
-/// blocks get filled by allocation requests. To make allocations in the
-/// arena use the `Allocator` returned by `allocator`. Only one `Allocator`
-/// can be active for an arena at a time, after the allocator is dropped
-/// the space used by its allocations is made available again.
+/// The `MemoryArena` allocates an initial block on creation and grows new
+/// blocks on demand as its existing blocks get filled by allocation requests.
+/// New blocks are sized geometrically (the larger of the arena's running
+/// total capacity and the size of the request that needed them), so a
+/// workload that allocates far more than one block's worth converges to a
+/// steady-state number of blocks instead of growing linearly. To make
+/// allocations in the arena use the `Allocator` returned by `allocator`.
+/// Only one `Allocator` can be active for an arena at a time, after the
+/// allocator is dropped the space used by its allocations is made available
+/// again, and the blocks grown so far are kept around for reuse by the next
+/// `Allocator`.
 pub struct MemoryArena {
     blocks: Vec<Block>,
-    block_size: usize,
+    total_capacity: usize,
 }
 
 impl MemoryArena {
-    /// Create a new `MemoryArena` with the requested block size (in MB).
-    /// The arena will allocate one initial block on creation, and further
-    /// blocks of `block_size_mb` size, or larger if needed to meet a large
+    /// Create a new `MemoryArena` with the requested initial block size (in
+    /// MB). The arena will allocate one initial block on creation, and grow
+    /// further blocks geometrically, or larger if needed to meet a large
     /// allocation, on demand as allocations are made.
     pub fn new(block_size_mb: usize) -> MemoryArena {
         let block_size = block_size_mb * 1024 * 1024;
         MemoryArena {
             blocks: vec![Block::new(block_size)],
-            block_size: block_size,
+            total_capacity: block_size,
         }
     }
     /// Get an allocator for the arena. Only a single `Allocator` can be
@@ -146,22 +165,43 @@ impl MemoryArena {
     pub fn allocator(&mut self) -> Allocator {
         Allocator {
             arena: RefCell::new(self),
+            marker_depth: Cell::new(0),
+            drop_fns: RefCell::new(Vec::new()),
         }
     }
+    /// The total number of bytes reserved across all of the arena's blocks,
+    /// i.e. the high-water mark the arena has grown to so far. This only
+    /// grows as new blocks are added; it never shrinks when an `Allocator`
+    /// is dropped.
+    pub fn capacity(&self) -> usize {
+        self.total_capacity
+    }
     /// Reserve a chunk of bytes in some block of the memory arena
     unsafe fn reserve(&mut self, size: usize, align: usize) -> *mut u8 {
-        for b in &mut self.blocks[..] {
+        self.reserve_indexed(size, align).0
+    }
+    /// Reserve a chunk of bytes in some block of the memory arena, also
+    /// returning the index of the block it was reserved from so a caller
+    /// that needs several reservations to stay contiguous (e.g.
+    /// `Allocator::alloc_iter`) can target that same block directly instead
+    /// of going through the general scan-from-the-start lookup again.
+    unsafe fn reserve_indexed(&mut self, size: usize, align: usize) -> (*mut u8, usize) {
+        for (i, b) in self.blocks.iter_mut().enumerate() {
             if b.has_room(size, align) {
-                return b.reserve(size, align);
+                return (b.reserve(size, align), i);
             }
         }
-        // No free blocks with enough room, we have to allocate. We also make
-        // sure we've got align bytes of padding available as we don't assume
-        // anything about the alignment of the underlying buffer.
-        let new_block_size = cmp::max(self.block_size, size + align);
+        // No existing block has room: grow geometrically instead of adding
+        // another fixed-size block, so repeated scopes (e.g. one per pixel
+        // rendered) converge to a steady-state high-water mark of blocks
+        // and stop growing once warmed up. We also make sure we've got
+        // align bytes of padding available as we don't assume anything
+        // about the alignment of the underlying buffer.
+        let new_block_size = cmp::max(self.total_capacity, size + align);
+        self.total_capacity += new_block_size;
         self.blocks.push(Block::new(new_block_size));
-        let b = &mut self.blocks.last_mut().unwrap();
-        b.reserve(size, align)
+        let idx = self.blocks.len() - 1;
+        (self.blocks[idx].reserve(size, align), idx)
     }
 }
 
@@ -170,12 +210,74 @@ impl MemoryArena {
 ///
 /// Objects allocated by an allocated cannot outlive it, upon destruction
 /// of the allocator the memory space it requested will be made available
-/// again. **Drops of allocated objects are not called**, only
-/// types which are `Sized + Copy` can be safely stored.
+/// again. **Drops of objects allocated with `alloc`/`alloc_slice` are not
+/// called**, so only types which are `Sized + Copy` can be safely stored
+/// that way; use `alloc_drop` to store a non-`Copy` type that needs its
+/// destructor run when the `Allocator` scope ends.
 pub struct Allocator<'a> {
     arena: RefCell<&'a mut MemoryArena>,
+    marker_depth: Cell<usize>,
+    drop_fns: RefCell<Vec<(*mut u8, unsafe fn(*mut u8))>>,
+}
+
+/// A savepoint within an `Allocator`'s blocks, captured by `Allocator::marker`
+/// and later passed to `Allocator::reset_to` to rewind the allocator back to
+/// that point, discarding anything allocated in between (running the
+/// destructor of anything allocated with `alloc_drop` since the marker was
+/// taken).
+pub struct Marker {
+    sizes: Vec<usize>,
+    drop_len: usize,
+    depth: usize,
 }
+
 impl<'a> Allocator<'a> {
+    /// Take a savepoint of the allocator's current fill. Passing the returned
+    /// `Marker` to `reset_to` later discards everything allocated since this
+    /// call, without tearing down the `Allocator` scope itself. This lets a
+    /// single `Allocator` be reused across many sub-tasks, rewinding between
+    /// each instead of dropping and re-acquiring the arena's allocator.
+    pub fn marker(&self) -> Marker {
+        let arena = self.arena.borrow();
+        let depth = self.marker_depth.get() + 1;
+        self.marker_depth.set(depth);
+        Marker {
+            sizes: arena.blocks.iter().map(|b| b.size).collect(),
+            drop_len: self.drop_fns.borrow().len(),
+            depth: depth,
+        }
+    }
+    /// Rewind the allocator to a previously taken `Marker`, discarding
+    /// everything allocated since (running the destructor of anything
+    /// allocated with `alloc_drop` in that span, in reverse allocation
+    /// order, before its backing memory is handed out again). Markers must
+    /// be reset in the reverse (LIFO) order they were taken in; resetting an
+    /// older marker while a more recent one is still outstanding is a bug.
+    pub fn reset_to(&self, marker: Marker) {
+        debug_assert_eq!(
+            marker.depth,
+            self.marker_depth.get(),
+            "markers must be reset in LIFO order"
+        );
+
+        let mut drop_fns = self.drop_fns.borrow_mut();
+        for &(ptr, drop_fn) in drop_fns[marker.drop_len..].iter().rev() {
+            unsafe {
+                drop_fn(ptr);
+            }
+        }
+        drop_fns.truncate(marker.drop_len);
+        drop(drop_fns);
+
+        let mut arena = self.arena.borrow_mut();
+        for (b, size) in arena.blocks.iter_mut().zip(marker.sizes.iter()) {
+            b.size = *size;
+        }
+        for b in arena.blocks.iter_mut().skip(marker.sizes.len()) {
+            b.size = 0;
+        }
+        self.marker_depth.set(marker.depth - 1);
+    }
     /// Get a dynamically sized slice of data from the allocator. The
     /// contents of the slice will be unintialized.
     pub fn alloc_slice<T: Sized + Copy>(&self, len: usize) -> &mut [T] {
@@ -187,6 +289,76 @@ impl<'a> Allocator<'a> {
         }
     }
 
+    /// Get a dynamically sized slice of data from the allocator, with every
+    /// element initialized to `value`.
+    pub fn alloc_slice_fill<T: Sized + Copy>(&self, len: usize, value: T) -> &mut [T] {
+        let slice = self.alloc_slice::<T>(len);
+        for v in slice.iter_mut() {
+            *v = value;
+        }
+        slice
+    }
+
+    /// Allocate a slice from the contents of an iterator.
+    ///
+    /// If `iter`'s `size_hint` lower bound undercounts the actual number of
+    /// items it yields, the remaining items are reserved one at a time from
+    /// the same block the initial reservation came from, and must stay
+    /// contiguous with what's already been written; if that block fills up
+    /// before the iterator is exhausted this panics, since the slice
+    /// returned must be a single contiguous region. That panic happens
+    /// mid-iteration, after some items have already been written into the
+    /// arena but before the rest of `iter` has been consumed or a slice has
+    /// been handed back to the caller; for a `T` with a destructor those
+    /// already-written items would be unreachable and leaked (the arena has
+    /// no way to learn of them to run their drop glue). To keep this method
+    /// leak-free rather than documenting that hazard, `T` is restricted to
+    /// `Copy`, same as `alloc_slice`/`alloc_slice_fill`; use `alloc_drop`
+    /// item-by-item if you need to store non-`Copy` values from an iterator.
+    pub fn alloc_iter<T: Copy, I: IntoIterator<Item = T>>(&self, iter: I) -> &mut [T] {
+        let mut iter = iter.into_iter();
+        let (lower, _) = iter.size_hint();
+
+        let mut arena = self.arena.borrow_mut();
+        let (base, block_idx) = unsafe {
+            arena.reserve_indexed(lower * mem::size_of::<T>(), mem::align_of::<T>())
+        };
+        let base = base as *mut T;
+
+        let mut len = 0;
+        while len < lower {
+            match iter.next() {
+                Some(item) => unsafe {
+                    ptr::write(base.offset(len as isize), item);
+                    len += 1;
+                },
+                None => break,
+            }
+        }
+
+        // The iterator undercounted its size hint; keep reserving one
+        // element at a time from the same block the initial reservation
+        // came from, as long as it keeps handing back space contiguous
+        // with what we've already written.
+        for item in iter {
+            unsafe {
+                let next_ptr = arena.blocks[block_idx]
+                    .reserve(mem::size_of::<T>(), mem::align_of::<T>())
+                    as *mut T;
+                assert_eq!(
+                    next_ptr,
+                    base.offset(len as isize),
+                    "alloc_iter: iterator yielded more items than its size hint, and the \
+                     arena could not keep the allocation contiguous"
+                );
+                ptr::write(next_ptr, item);
+                len += 1;
+            }
+        }
+
+        unsafe { std::slice::from_raw_parts_mut(base, len) }
+    }
+
     pub fn alloc<T: Sized + Copy>(&self, object: T) -> &mut T {
         assert!(!mem::needs_drop::<T>());
         // assert!(mem::size_of::<T>() != 0);
@@ -199,12 +371,93 @@ impl<'a> Allocator<'a> {
             &mut *(ptr as *mut T)
         }
     }
+
+    /// Like `alloc`, but without the `Copy` restriction: `object`'s destructor
+    /// (if it has one) is recorded and run when the `Allocator` itself is
+    /// dropped, or earlier by `reset_to` if the object was allocated after
+    /// the `Marker` being reset to, so types like `Box`-holding structs or `String`s can be
+    /// stored safely. This is slower than `alloc` since it has to track the
+    /// object for later cleanup, so prefer `alloc`/`alloc_slice` for `Copy`
+    /// data.
+    pub fn alloc_drop<T: Sized>(&self, object: T) -> &mut T {
+        let mut arena = self.arena.borrow_mut();
+        unsafe {
+            let ptr = arena.reserve(mem::size_of::<T>(), mem::align_of::<T>());
+            ptr::write(ptr as *mut T, object);
+
+            if mem::needs_drop::<T>() {
+                unsafe fn drop_glue<T>(ptr: *mut u8) {
+                    ptr::drop_in_place(ptr as *mut T);
+                }
+                self.drop_fns.borrow_mut().push((ptr, drop_glue::<T>));
+            }
+
+            &mut *(ptr as *mut T)
+        }
+    }
+}
+
+/// Lets `Allocator` back the standard collections, e.g. `Vec::new_in(&allocator)`
+/// or `Box::new_in(x, &allocator)`, by routing allocations through the arena's
+/// blocks. Since the arena never frees individual allocations, `deallocate` is
+/// a no-op, `grow` always allocates fresh space and copies into it, and
+/// `shrink` hands back the same region unchanged since there's nothing to
+/// reclaim by shrinking in place; space is only reclaimed in bulk when the
+/// `Allocator` itself is dropped.
+unsafe impl<'a> std::alloc::Allocator for Allocator<'a> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut arena = self.arena.borrow_mut();
+        let ptr = unsafe { arena.reserve(layout.size(), layout.align()) };
+        if ptr.is_null() {
+            return Err(AllocError);
+        }
+        let slice = unsafe { std::slice::from_raw_parts_mut(ptr, layout.size()) };
+        Ok(NonNull::from(slice))
+    }
+
+    unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {
+        // The arena reclaims everything in bulk when the `Allocator` drops,
+        // so there's nothing to do for a single allocation here.
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() >= old_layout.size());
+        let new_ptr = self.allocate(new_layout)?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr() as *mut u8, old_layout.size());
+        Ok(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() <= old_layout.size());
+        let slice = std::slice::from_raw_parts_mut(ptr.as_ptr(), new_layout.size());
+        Ok(NonNull::from(slice))
+    }
 }
 
 impl<'a> Drop for Allocator<'a> {
-    /// Upon dropping the allocator we mark all the blocks in the arena
-    /// as empty again, "releasing" our allocations.
+    /// Upon dropping the allocator we run the destructors of any objects
+    /// allocated with `alloc_drop`, in reverse allocation order, then mark
+    /// all the blocks in the arena as empty again, "releasing" our
+    /// allocations.
     fn drop(&mut self) {
+        let mut drop_fns = self.drop_fns.borrow_mut();
+        for &(ptr, drop_fn) in drop_fns.iter().rev() {
+            unsafe {
+                drop_fn(ptr);
+            }
+        }
+        drop_fns.clear();
+
         let mut arena = self.arena.borrow_mut();
         for b in &mut arena.blocks[..] {
             b.size = 0;
@@ -251,4 +504,15 @@ mod tests {
         assert_eq!(arena.blocks.len(), 2);
         assert_eq!(arena.blocks[1].buffer.capacity(), two_mb + 32);
     }
+
+    #[test]
+    fn reserve_indexed_returns_owning_block() {
+        let mut arena = MemoryArena::new(1);
+        let (_, idx0) = unsafe { arena.reserve_indexed(1024, 4) };
+        assert_eq!(idx0, 0);
+
+        let two_mb = 2 * 1024 * 1024;
+        let (_, idx1) = unsafe { arena.reserve_indexed(two_mb, 32) };
+        assert_eq!(idx1, 1);
+    }
 }
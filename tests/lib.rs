@@ -1,4 +1,5 @@
 #![feature(placement_in_syntax, attr_literals)]
+#![feature(allocator_api)]
 extern crate light_arena;
 
 use light_arena::MemoryArena;
@@ -214,3 +215,102 @@ fn dynamic_slice() {
     assert_eq!(x.as_ptr() as usize + std::mem::size_of::<usize>() * 16, y.as_ptr() as usize);
 }
 
+#[test]
+fn std_allocator_vec_and_box() {
+    let mut arena = MemoryArena::new(1);
+    let allocator = arena.allocator();
+
+    let mut v: Vec<u32, _> = Vec::new_in(&allocator);
+    for i in 0..64u32 {
+        v.push(i);
+    }
+    assert_eq!(v.len(), 64);
+    for (i, x) in v.iter().enumerate() {
+        assert_eq!(*x, i as u32);
+    }
+
+    let b = Box::new_in(42i64, &allocator);
+    assert_eq!(*b, 42);
+}
+
+#[test]
+fn marker_reuses_space() {
+    let mut arena = MemoryArena::new(1);
+    let allocator = arena.allocator();
+
+    let marker = allocator.marker();
+    let x = allocator.alloc_slice::<u32>(16);
+    let addr_a = x.as_ptr() as usize;
+    allocator.reset_to(marker);
+
+    let y = allocator.alloc_slice::<u32>(16);
+    let addr_b = y.as_ptr() as usize;
+    assert_eq!(addr_a, addr_b);
+}
+
+#[test]
+fn reset_to_drops_discarded_objects_exactly_once() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Track(Rc<RefCell<Vec<i32>>>, i32);
+    impl Drop for Track {
+        fn drop(&mut self) {
+            self.0.borrow_mut().push(self.1);
+        }
+    }
+
+    let drops = Rc::new(RefCell::new(Vec::new()));
+    {
+        let mut arena = MemoryArena::new(1);
+        let allocator = arena.allocator();
+
+        let marker = allocator.marker();
+        allocator.alloc_drop(Track(drops.clone(), 1));
+        allocator.reset_to(marker);
+        allocator.alloc_drop(Track(drops.clone(), 2));
+    }
+
+    // `Track(1)`'s destructor runs at `reset_to`, since it was allocated
+    // after the marker; `Track(2)`'s runs at scope exit. Each runs exactly
+    // once, in allocation order.
+    assert_eq!(*drops.borrow(), vec![1, 2]);
+}
+
+#[test]
+fn alloc_iter_and_slice_fill_contents() {
+    let mut arena = MemoryArena::new(1);
+    let allocator = arena.allocator();
+
+    let filled = allocator.alloc_slice_fill::<u8>(8, 7);
+    assert_eq!(filled, &[7u8; 8]);
+
+    let iterated = allocator.alloc_iter((0..10u32).map(|i| i * i));
+    assert_eq!(iterated.len(), 10);
+    for (i, v) in iterated.iter().enumerate() {
+        assert_eq!(*v, (i as u32) * (i as u32));
+    }
+}
+
+#[test]
+fn capacity_grows_to_a_high_water_mark() {
+    let mut arena = MemoryArena::new(1);
+    let initial = arena.capacity();
+    assert_eq!(initial, 1024 * 1024);
+
+    {
+        let allocator = arena.allocator();
+        let _ = allocator.alloc_slice::<u8>(2 * 1024 * 1024);
+    }
+    let grown = arena.capacity();
+    assert!(grown > initial);
+
+    // Reusing the arena for another scope without exceeding what it's
+    // already grown to must not grow it again.
+    {
+        let allocator = arena.allocator();
+        let _ = allocator.alloc_slice::<u8>(1024);
+    }
+    assert_eq!(arena.capacity(), grown);
+}
+